@@ -0,0 +1,181 @@
+//! Raster image -> WebP/AVIF conversion and reference rewriting.
+//!
+//! Converting an image changes its file extension (`images/cover.jpg` ->
+//! `images/cover.webp`), so every other entry that names it - the OPF
+//! manifest and any XHTML/CSS referencing it - has to be patched to match.
+//! The flow is: build a rename table from the entries that will be
+//! converted, then apply it to every text entry in a second pass.
+//!
+//! Renames are keyed on the full archive path, but `src`/`href`/`url()`
+//! references are relative to the referencing file's own directory, so
+//! [`rewrite_references`] and [`rewrite_opf_manifest`] resolve both the old
+//! and new archive paths relative to the referencing entry before matching.
+
+use std::collections::HashMap;
+use clap::ValueEnum;
+use image::EncodableLayout;
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ImageFormat {
+    Webp,
+    Avif,
+}
+
+impl ImageFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            ImageFormat::Webp => "webp",
+            ImageFormat::Avif => "avif",
+        }
+    }
+
+    pub fn media_type(self) -> &'static str {
+        match self {
+            ImageFormat::Webp => "image/webp",
+            ImageFormat::Avif => "image/avif",
+        }
+    }
+
+    /// The minimum EPUB package `version` that reading systems are expected
+    /// to support this format in, per the OPS 3 spec.
+    pub fn min_epub_version(self) -> &'static str {
+        match self {
+            ImageFormat::Webp => "3.3",
+            ImageFormat::Avif => "3.3",
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ConvertError {
+    #[error("{0}")]
+    Image(#[from] image::ImageError),
+    #[error("failed to encode AVIF: {0}")]
+    Avif(String),
+}
+
+/// Extensions we know how to decode and re-encode.
+pub fn is_convertible(name: &str) -> bool {
+    let lower = name.to_ascii_lowercase();
+    lower.ends_with(".jpg") || lower.ends_with(".jpeg") || lower.ends_with(".png") || lower.ends_with(".gif")
+}
+
+/// Swaps the extension of `name` for `format`'s, keeping the rest of the path.
+pub fn renamed(name: &str, format: ImageFormat) -> String {
+    match name.rsplit_once('.') {
+        Some((stem, _ext)) => format!("{stem}.{}", format.extension()),
+        None => format!("{name}.{}", format.extension()),
+    }
+}
+
+/// Decodes `data` with the `image` crate and re-encodes it as `format`.
+pub fn convert_image(format: ImageFormat, data: &[u8]) -> Result<Vec<u8>, ConvertError> {
+    let decoded = image::load_from_memory(data)?.to_rgba8();
+    let (width, height) = decoded.dimensions();
+
+    let encoded = match format {
+        ImageFormat::Webp => {
+            let encoder = webp::Encoder::from_rgba(decoded.as_bytes(), width, height);
+            encoder.encode(80.0).to_vec()
+        }
+        ImageFormat::Avif => {
+            let encoder = ravif::Encoder::new().with_quality(80.0).with_speed(4);
+            let img = ravif::Img::new(
+                rgb::FromSlice::as_rgba(decoded.as_bytes()),
+                width as usize,
+                height as usize,
+            );
+            encoder
+                .encode_rgba(img)
+                .map_err(|e| ConvertError::Avif(e.to_string()))?
+                .avif_file
+        }
+    };
+    Ok(encoded)
+}
+
+/// Maps an original archive entry name to its converted name and media type.
+pub type RenameTable = HashMap<String, (String, &'static str)>;
+
+/// The directory portion of an archive path (`""` if it has none).
+fn dirname(path: &str) -> &str {
+    path.rsplit_once('/').map(|(dir, _)| dir).unwrap_or("")
+}
+
+/// Expresses `to_path` (an archive path) relative to `from_dir` (another
+/// entry's directory), the way an href inside that entry would reference it.
+fn relative_to(from_dir: &str, to_path: &str) -> String {
+    let from_parts: Vec<&str> = if from_dir.is_empty() { Vec::new() } else { from_dir.split('/').collect() };
+    let to_parts: Vec<&str> = to_path.split('/').collect();
+    let to_dir_len = to_parts.len().saturating_sub(1);
+    let common = from_parts
+        .iter()
+        .zip(to_parts.iter())
+        .take(to_dir_len.min(from_parts.len()))
+        .take_while(|(a, b)| a == b)
+        .count();
+    let ups = std::iter::repeat("..").take(from_parts.len() - common);
+    ups.chain(to_parts[common..].iter().copied()).collect::<Vec<_>>().join("/")
+}
+
+/// Rewrites every `old -> new` occurrence of a renamed image inside `src=`,
+/// `href=`, and CSS `url(...)` references found in `referencing_path`.
+/// Deliberately does plain substring replacement rather than a full
+/// (X)HTML/CSS parse, since a renamed file's relative path is unambiguous
+/// enough inside a single referencing entry.
+pub fn rewrite_references(content: &str, renames: &RenameTable, referencing_path: &str) -> String {
+    let dir = dirname(referencing_path);
+    let mut out = content.to_string();
+    for (old, (new, _media_type)) in renames {
+        let old_rel = relative_to(dir, old);
+        let new_rel = relative_to(dir, new);
+        out = out.replace(old_rel.as_str(), new_rel.as_str());
+    }
+    out
+}
+
+/// Rewrites the `href` and `media-type` of every `<item>` in an OPF manifest
+/// whose `href` (resolved relative to the OPF's own directory) matches a
+/// renamed image.
+pub fn rewrite_opf_manifest(content: &str, renames: &RenameTable, referencing_path: &str) -> String {
+    let dir = dirname(referencing_path);
+    let mut out = content.to_string();
+    for (old, (new, media_type)) in renames {
+        let old_rel = relative_to(dir, old);
+        let new_rel = relative_to(dir, new);
+
+        // scope the href/media-type patch to the single <item> element that
+        // references this entry, so a sibling manifest entry's media-type
+        // can never be touched
+        // only a whole quoted attribute value matches, not a longer sibling
+        // path that merely ends with `old_rel`
+        let Some(href_pos) = out.match_indices(old_rel.as_str()).map(|(pos, _)| pos).find(|&pos| {
+            let quote = out[..pos].chars().next_back();
+            matches!(quote, Some('"' | '\'')) && out[pos + old_rel.len()..].chars().next() == quote
+        }) else { continue };
+        let elem_start = out[..href_pos].rfind("<item").unwrap_or(href_pos);
+        let Some(elem_end_rel) = out[href_pos..].find('>') else { continue };
+        let elem_end = href_pos + elem_end_rel + 1;
+
+        let mut element = out[elem_start..elem_end].replace(old_rel.as_str(), new_rel.as_str());
+        if let Some(attr_start) = element.find("media-type=\"") {
+            let value_start = attr_start + "media-type=\"".len();
+            if let Some(value_end) = element[value_start..].find('"') {
+                element.replace_range(value_start..value_start + value_end, media_type);
+            }
+        }
+        out.replace_range(elem_start..elem_end, &element);
+    }
+    out
+}
+
+/// Reads the `version` attribute off the OPF `<package>` element, used to
+/// decide whether a target format is safe to emit without `--force-convert`.
+pub fn opf_version(opf_content: &str) -> Option<&str> {
+    let package_start = opf_content.find("<package")?;
+    let tag_end = package_start + opf_content[package_start..].find('>')?;
+    let tag = &opf_content[package_start..tag_end];
+    let attr_start = tag.find("version=\"")? + "version=\"".len();
+    let attr_end = attr_start + tag[attr_start..].find('"')?;
+    Some(&tag[attr_start..attr_end])
+}
@@ -0,0 +1,25 @@
+//! Error types for `epubshrink`.
+//!
+//! Anything that makes the whole run unusable (the archive can't be opened,
+//! the input isn't actually an EPUB) is a fatal [`Error`]. Anything scoped to
+//! a single entry (a malformed image, a non-UTF-8 XHTML file) is instead
+//! logged as a warning by the caller and that entry is passed through
+//! unmodified, so a single bad entry doesn't sink the whole conversion.
+
+use std::path::PathBuf;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("failed to open {0:?}: {1}")]
+    Open(PathBuf, #[source] std::io::Error),
+    #[error("failed to create {0:?}: {1}")]
+    Create(PathBuf, #[source] std::io::Error),
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("invalid ZIP archive: {0}")]
+    Zip(#[from] zip::result::ZipError),
+    #[error("does not look like an EPUB: {0}")]
+    NotAnEpub(String),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
@@ -0,0 +1,153 @@
+//! Font detection and subsetting helpers.
+//!
+//! `subsetter` only understands raw SFNT (`.otf`/`.ttf`) data, so WOFF and
+//! WOFF2 containers are unwrapped to SFNT before subsetting and rewrapped
+//! afterwards. The set of code points to keep is built from the actual text
+//! the EPUB contains rather than a blanket Latin-1 range, so fonts that only
+//! cover a handful of glyphs shrink accordingly.
+
+use std::collections::HashSet;
+
+/// Code points that are always kept regardless of what text was scanned,
+/// since EPUB readers can rely on basic whitespace being present in a font.
+pub const DEFAULT_ALWAYS_KEEP: &[u32] = &[0x09, 0x0A, 0x0D, 0x20];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Container {
+    Sfnt,
+    Woff,
+    Woff2,
+}
+
+/// Classifies a font entry by its extension.
+pub fn container_of(name: &str) -> Option<Container> {
+    let lower = name.to_ascii_lowercase();
+    if lower.ends_with(".woff2") {
+        Some(Container::Woff2)
+    } else if lower.ends_with(".woff") {
+        Some(Container::Woff)
+    } else if lower.ends_with(".otf") || lower.ends_with(".ttf") {
+        Some(Container::Sfnt)
+    } else {
+        None
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("failed to decompress WOFF: {0}")]
+    WoffDecompress(String),
+    #[error("failed to recompress WOFF: {0}")]
+    WoffCompress(String),
+    #[error("failed to decompress WOFF2: {0}")]
+    Woff2Decompress(String),
+    #[error("failed to recompress WOFF2: {0}")]
+    Woff2Compress(String),
+    #[error("failed to subset font: {0}")]
+    Subset(String),
+}
+
+fn to_sfnt(container: Container, data: &[u8]) -> Result<Vec<u8>, Error> {
+    match container {
+        Container::Sfnt => Ok(data.to_vec()),
+        Container::Woff => woff::version1::decompress(data).map_err(|e| Error::WoffDecompress(format!("{e:?}"))),
+        Container::Woff2 => woff2::decode::decode(data).map_err(|e| Error::Woff2Decompress(format!("{e:?}"))),
+    }
+}
+
+fn from_sfnt(container: Container, sfnt: &[u8]) -> Result<Vec<u8>, Error> {
+    match container {
+        Container::Sfnt => Ok(sfnt.to_vec()),
+        Container::Woff => woff::version1::compress(sfnt, Default::default()).map_err(|e| Error::WoffCompress(format!("{e:?}"))),
+        Container::Woff2 => woff2::encode::encode(sfnt).map_err(|e| Error::Woff2Compress(format!("{e:?}"))),
+    }
+}
+
+/// Unwraps `data` to SFNT if needed, subsets it down to `used_chars`, and
+/// rewraps it in its original container.
+pub fn subset(container: Container, data: &[u8], used_chars: &[u32]) -> Result<Vec<u8>, Error> {
+    let sfnt = to_sfnt(container, data)?;
+    let profile = subsetter::Profile::pdf(used_chars);
+    let subsetted = subsetter::subset(&sfnt, 0, profile).map_err(|e| Error::Subset(format!("{e:?}")))?;
+    from_sfnt(container, &subsetted)
+}
+
+/// Collects the Unicode scalar values used in the text nodes of `xhtml`
+/// into `out`, skipping markup so tag and attribute names don't inflate the
+/// kept-glyph set. Numeric and common named character references (e.g.
+/// `&#8217;`, `&mdash;`, `&amp;`) are decoded first, since text that relies
+/// on them (curly quotes, dashes, ellipses) is otherwise excluded from the
+/// subset and renders as tofu.
+pub fn collect_code_points(xhtml: &str, out: &mut HashSet<u32>) {
+    let mut in_tag = false;
+    let mut chars = xhtml.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '<' => in_tag = true,
+            '>' => in_tag = false,
+            '&' if !in_tag => match decode_entity(&xhtml[i..]) {
+                Some((code_point, len)) => {
+                    out.insert(code_point);
+                    for _ in 0..len - 1 {
+                        chars.next();
+                    }
+                }
+                None => {
+                    out.insert('&' as u32);
+                }
+            },
+            _ if !in_tag => {
+                out.insert(c as u32);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Decodes a character reference at the start of `s` (which must begin with
+/// `&`), returning the decoded code point and the reference's length in
+/// characters (including the leading `&` and trailing `;`).
+fn decode_entity(s: &str) -> Option<(u32, usize)> {
+    let end = s[1..].find(';')? + 1;
+    let body = &s[1..end];
+    if body.is_empty() || body.len() > 10 {
+        return None;
+    }
+
+    let code_point = if let Some(rest) = body.strip_prefix('#') {
+        if let Some(hex) = rest.strip_prefix('x').or_else(|| rest.strip_prefix('X')) {
+            u32::from_str_radix(hex, 16).ok()?
+        } else {
+            rest.parse().ok()?
+        }
+    } else {
+        named_entity(body)?
+    };
+    char::from_u32(code_point)?;
+    Some((code_point, end + 1))
+}
+
+/// The handful of named character references common in EPUB text: the five
+/// built into XML, plus typographic punctuation that's often encoded by
+/// name instead of by numeric reference.
+fn named_entity(name: &str) -> Option<u32> {
+    Some(match name {
+        "amp" => 0x26,
+        "lt" => 0x3C,
+        "gt" => 0x3E,
+        "quot" => 0x22,
+        "apos" => 0x27,
+        "nbsp" => 0xA0,
+        "copy" => 0xA9,
+        "reg" => 0xAE,
+        "trade" => 0x2122,
+        "ndash" => 0x2013,
+        "mdash" => 0x2014,
+        "lsquo" => 0x2018,
+        "rsquo" => 0x2019,
+        "ldquo" => 0x201C,
+        "rdquo" => 0x201D,
+        "hellip" => 0x2026,
+        _ => return None,
+    })
+}
@@ -0,0 +1,446 @@
+//! Core EPUB-shrinking logic, usable independently of the CLI in `main.rs`.
+//!
+//! [`shrink`] reads a whole EPUB from any `Read + Seek` source, minimizes it
+//! according to [`ShrinkOptions`], and writes the result to any
+//! `Write + Seek` sink, returning per-category byte savings as
+//! [`ShrinkStats`].
+
+pub mod convert;
+pub mod error;
+pub mod font;
+
+use std::collections::HashSet;
+use std::io::{Read, Seek, Write};
+use std::sync::mpsc;
+use std::thread;
+use caesium::initialize_parameters;
+use caesium::jpeg::ChromaSubsampling;
+use clap::ValueEnum;
+use convert::{ImageFormat, RenameTable};
+pub use error::{Error, Result};
+use zip::write::FileOptions;
+use zip::CompressionMethod;
+
+/// What to do and how, mirroring the CLI flags in `main.rs` one-to-one.
+#[derive(Clone, Debug)]
+pub struct ShrinkOptions {
+    /// JPEG recompression quality (1-99), used when `images` is set and `convert` isn't.
+    pub jpeg_quality: u32,
+    /// Subsets fonts down to the code points actually used in the EPUB's text.
+    pub fonts: bool,
+    /// Recompresses JPEG images with Caesium.
+    pub images: bool,
+    /// Trims leading/trailing whitespace from each line of every XHTML file.
+    pub xhtml: bool,
+    /// Number of worker threads used to compress images and fonts.
+    pub jobs: usize,
+    /// Converts raster images to a more modern codec instead of recompressing them.
+    pub convert: Option<ImageFormat>,
+    /// Converts even if the EPUB's declared version predates reading-system support for the target format.
+    pub force_convert: bool,
+    /// ZIP compression method used for rewritten entries (the `mimetype` entry is always copied through verbatim, unaffected by this).
+    pub zip_method: ZipMethod,
+    /// ZIP compression level for `zip_method` (method-dependent range).
+    pub zip_level: Option<i64>,
+    /// Extra characters to always keep in subsetted fonts, on top of basic whitespace.
+    pub always_keep: Option<String>,
+}
+
+impl Default for ShrinkOptions {
+    fn default() -> Self {
+        ShrinkOptions {
+            jpeg_quality: 50,
+            fonts: false,
+            images: false,
+            xhtml: false,
+            jobs: default_jobs(),
+            convert: None,
+            force_convert: false,
+            zip_method: ZipMethod::Deflated,
+            zip_level: None,
+            always_keep: None,
+        }
+    }
+}
+
+pub fn default_jobs() -> usize {
+    thread::available_parallelism().map(|n| n.get()).unwrap_or(1)
+}
+
+/// CLI-facing mirror of `zip::CompressionMethod`'s non-legacy variants.
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum ZipMethod {
+    Stored,
+    Deflated,
+    Bzip2,
+    Zstd,
+}
+
+impl From<ZipMethod> for CompressionMethod {
+    fn from(method: ZipMethod) -> Self {
+        match method {
+            ZipMethod::Stored => CompressionMethod::Stored,
+            ZipMethod::Deflated => CompressionMethod::Deflated,
+            ZipMethod::Bzip2 => CompressionMethod::Bzip2,
+            ZipMethod::Zstd => CompressionMethod::Zstd,
+        }
+    }
+}
+
+/// Bytes before/after minimization for one entry category.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CategoryStats {
+    pub bytes_before: u64,
+    pub bytes_after: u64,
+}
+
+impl CategoryStats {
+    fn record(&mut self, before: usize, after: usize) {
+        self.bytes_before += before as u64;
+        self.bytes_after += after as u64;
+    }
+
+    /// Positive when the category shrank, negative when it grew.
+    pub fn bytes_saved(&self) -> i64 {
+        self.bytes_before as i64 - self.bytes_after as i64
+    }
+}
+
+/// Per-category byte savings returned by [`shrink`].
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ShrinkStats {
+    pub images: CategoryStats,
+    pub fonts: CategoryStats,
+    pub xhtml: CategoryStats,
+}
+
+fn zip_options(opts: &ShrinkOptions) -> FileOptions<'static, ()> {
+    let mut options = FileOptions::default().compression_method(opts.zip_method.into());
+    match (opts.zip_method, opts.zip_level) {
+        // the zip crate rejects a compression level on a Stored entry, and
+        // there's nothing for a level to tune without compression anyway
+        (ZipMethod::Stored, Some(_)) => {
+            log::warn!("zip_level has no effect with zip_method Stored, ignoring it");
+        }
+        (_, Some(level)) => {
+            options = options.compression_level(Some(level));
+        }
+        (_, None) => {}
+    }
+    options
+}
+
+/// A CPU-bound entry that still needs to be compressed by the worker pool.
+enum Job {
+    Image(Vec<u8>),
+    Convert(Vec<u8>, ImageFormat),
+    Font(Vec<u8>, font::Container),
+}
+
+/// What a given archive index resolves to once it has been read and
+/// (if it's a `Job`) before the worker pool has processed it.
+enum Entry {
+    Job(Job),
+    Done(Vec<u8>),
+}
+
+/// Reads a whole EPUB from `input`, minimizes it according to `opts`, and
+/// writes the result to `output`.
+pub fn shrink<R: Read + Seek, W: Write + Seek>(input: R, output: W, opts: &ShrinkOptions) -> Result<ShrinkStats> {
+    let mut archive = zip::ZipArchive::new(input)?;
+    validate_epub(&mut archive)?;
+    let mut zip = zip::ZipWriter::new(output);
+    let mut stats = ShrinkStats::default();
+
+    // the mimetype entry must be physically first in the archive and
+    // stored without compression for the EPUB to be valid; raw_copy_file
+    // preserves its exact bytes, compression method and extra field
+    // instead of re-encoding it like every other entry, so it's handled
+    // here, up front, rather than through the rest of the pipeline
+    zip.raw_copy_file(archive.by_name("mimetype")?)?;
+
+    // prepare parameters for compressing images
+    let mut cs_params = initialize_parameters();
+    cs_params.keep_metadata = true;
+    cs_params.jpeg.quality = opts.jpeg_quality;
+    cs_params.jpeg.chroma_subsampling = ChromaSubsampling::CS411;
+
+    // seed the set of kept code points with basic whitespace plus anything
+    // the caller asked to always keep; the rest is filled in from the
+    // actual text content of the EPUB below
+    let mut used_chars: HashSet<u32> = font::DEFAULT_ALWAYS_KEEP.iter().copied().collect();
+    if let Some(extra) = &opts.always_keep {
+        for c in extra.chars() {
+            used_chars.insert(c as u32);
+        }
+    }
+
+    // if conversion was requested, check upfront whether the EPUB declares
+    // a version new enough for reading systems to be expected to support
+    // the target format, unless the caller overrides that with force_convert
+    let convert_format = opts.convert.filter(|format| {
+        opts.force_convert || match epub_version(&mut archive) {
+            Some(version) => version.as_str() >= format.min_epub_version(),
+            None => {
+                log::warn!("Could not determine EPUB version, skipping image conversion");
+                false
+            }
+        }
+    });
+    if opts.convert.is_some() && convert_format.is_none() {
+        log::warn!(
+            "EPUB version predates reading-system support for {:?}; enable force_convert to convert anyway",
+            opts.convert.unwrap()
+        );
+    }
+
+    // first pass (main thread only): decode every entry into an owned
+    // buffer. images and fonts become `Job`s for the worker pool below,
+    // everything else (including trimmed xhtml) needs no further compression,
+    // though OPF/XHTML/CSS entries may still get their references rewritten.
+    // `names` holds the original archive names (used to match references
+    // below); `final_names` is built once conversion results are known,
+    // since an entry whose conversion fails keeps its original name
+    let mut names = Vec::with_capacity(archive.len());
+    let mut entries = Vec::with_capacity(archive.len());
+    for i in 0..archive.len() {
+        let mut file = archive.by_index(i)?;
+        if file.name() == "mimetype" {
+            // already written above via raw_copy_file
+            continue;
+        }
+        names.push(file.name().to_string());
+
+        let entry = if let Some(format) = convert_format.filter(|_| file.is_file() && convert::is_convertible(file.name())) {
+            log::trace!("Queueing image {} for conversion", file.name());
+            let mut in_data: Vec<u8> = Vec::new();
+            file.read_to_end(&mut in_data)?;
+            Entry::Job(Job::Convert(in_data, format))
+        } else if opts.images && file.is_file() && file.name().ends_with(".jpg") {
+            log::trace!("Queueing image {}", file.name());
+            let mut in_data: Vec<u8> = Vec::new();
+            file.read_to_end(&mut in_data)?;
+            Entry::Job(Job::Image(in_data))
+        } else if let Some(container) = font::container_of(file.name()).filter(|_| opts.fonts && file.is_file()) {
+            log::trace!("Queueing font {}", file.name());
+            let mut in_data: Vec<u8> = Vec::new();
+            file.read_to_end(&mut in_data)?;
+            Entry::Job(Job::Font(in_data, container))
+        } else if (opts.xhtml || opts.fonts) && file.is_file() && file.name().ends_with(".xhtml") {
+            log::trace!("Scanning xhtml {}", file.name());
+
+            // read the XHTML file
+            let mut in_data: Vec<u8> = Vec::new();
+            file.read_to_end(&mut in_data)?;
+
+            // a non-UTF-8 XHTML file is malformed but not fatal: skip the
+            // character scan/trim for it and copy it through unmodified
+            match String::from_utf8(in_data.clone()) {
+                Ok(buffer) => {
+                    // keep track of used characters, skipping markup so
+                    // tag/attribute names don't inflate the kept-glyph set
+                    if opts.fonts {
+                        font::collect_code_points(&buffer, &mut used_chars);
+                    }
+
+                    let out_data = if opts.xhtml {
+                        // trim each line
+                        let mut new_buffer = String::new();
+                        for x in buffer.lines() {
+                            new_buffer.push_str(x.trim());
+                            new_buffer.push_str("\r\n");
+                        }
+                        new_buffer.into_bytes()
+                    } else {
+                        in_data.clone()
+                    };
+                    stats.xhtml.record(in_data.len(), out_data.len());
+                    Entry::Done(out_data)
+                }
+                Err(_) => {
+                    log::warn!("{} is not valid UTF-8, copying it unmodified", file.name());
+                    Entry::Done(in_data)
+                }
+            }
+        } else {
+            // copy any other file we dont use
+            let mut in_data: Vec<u8> = Vec::new();
+            file.read_to_end(&mut in_data)?;
+            Entry::Done(in_data)
+        };
+        entries.push(entry);
+    }
+    // the archive is only needed to decode entries, which just finished
+    drop(archive);
+
+    // second pass: dispatch the image and font jobs across a thread pool
+    // sized by `opts.jobs`, collecting `(index, data, renamed_to, is_font)`
+    // results through a channel. the ZipWriter never leaves the main thread
+    // since it isn't Sync. `renamed_to` is only set for a `Job::Convert` that
+    // actually succeeded, since a failed conversion keeps its original name
+    // and format
+    let used_chars = used_chars.drain().collect::<Vec<u32>>();
+    let job_indices: Vec<usize> = entries
+        .iter()
+        .enumerate()
+        .filter(|(_, entry)| matches!(entry, Entry::Job(_)))
+        .map(|(i, _)| i)
+        .collect();
+    let worker_count = opts.jobs.max(1).min(job_indices.len().max(1));
+    let (tx, rx) = mpsc::channel::<(usize, Vec<u8>, Option<(String, &'static str)>, bool)>();
+
+    thread::scope(|scope| {
+        for chunk in job_indices.chunks(job_indices.len().div_ceil(worker_count).max(1)) {
+            let tx = tx.clone();
+            let cs_params = &cs_params;
+            let used_chars = &used_chars;
+            let entries = &entries;
+            let names = &names;
+            scope.spawn(move || {
+                for &i in chunk {
+                    let Entry::Job(job) = &entries[i] else { unreachable!() };
+                    // a single entry failing to compress/convert/subset isn't
+                    // fatal: fall back to its original bytes and keep going
+                    let (result, renamed_to, is_font) = match job {
+                        Job::Image(in_data) => {
+                            log::trace!("Compressing image {}", names[i]);
+                            let mut params = cs_params.clone();
+                            let result = match caesium::compress_in_memory(in_data.clone(), &mut params) {
+                                Ok(result) => result,
+                                Err(err) => {
+                                    log::warn!("failed to compress image {}: {err}", names[i]);
+                                    in_data.clone()
+                                }
+                            };
+                            (result, None, false)
+                        }
+                        Job::Convert(in_data, format) => {
+                            log::trace!("Converting image {}", names[i]);
+                            match convert::convert_image(*format, in_data) {
+                                Ok(result) => {
+                                    let new_name = convert::renamed(&names[i], *format);
+                                    (result, Some((new_name, format.media_type())), false)
+                                }
+                                Err(err) => {
+                                    log::warn!("failed to convert image {}: {err}, keeping original format", names[i]);
+                                    (in_data.clone(), None, false)
+                                }
+                            }
+                        }
+                        Job::Font(in_data, container) => {
+                            log::trace!("Subsetting font {}", names[i]);
+                            let result = match font::subset(*container, in_data, used_chars) {
+                                Ok(result) => result,
+                                Err(err) => {
+                                    log::warn!("failed to subset font {}: {err}", names[i]);
+                                    in_data.clone()
+                                }
+                            };
+                            (result, None, true)
+                        }
+                    };
+                    tx.send((i, result, renamed_to, is_font)).expect("worker channel closed");
+                }
+            });
+        }
+    });
+    drop(tx);
+
+    let mut results: Vec<Option<Vec<u8>>> = vec![None; entries.len()];
+    let mut final_names = names.clone();
+    let mut renames: RenameTable = RenameTable::new();
+    for (index, data, renamed_to, is_font) in rx {
+        let before_len = match &entries[index] {
+            Entry::Job(Job::Image(d) | Job::Convert(d, _) | Job::Font(d, _)) => d.len(),
+            Entry::Done(_) => unreachable!("job result for a non-job entry"),
+        };
+        if is_font {
+            stats.fonts.record(before_len, data.len());
+        } else {
+            stats.images.record(before_len, data.len());
+        }
+        if let Some((new_name, media_type)) = renamed_to {
+            renames.insert(names[index].clone(), (new_name.clone(), media_type));
+            final_names[index] = new_name;
+        }
+        results[index] = Some(data);
+    }
+
+    // apply the name-remapping table: every OPF/XHTML/CSS entry gets its
+    // references patched to match the entries that were actually renamed
+    if !renames.is_empty() {
+        for (i, name) in names.iter().enumerate() {
+            if renames.contains_key(name) {
+                continue;
+            }
+            let Entry::Done(data) = &mut entries[i] else { continue };
+            let Ok(text) = std::str::from_utf8(data) else {
+                log::warn!("{name} is not valid UTF-8, skipping reference rewrite");
+                continue;
+            };
+            if name.ends_with(".opf") {
+                *data = convert::rewrite_opf_manifest(text, &renames, name).into_bytes();
+            } else if name.ends_with(".xhtml") || name.ends_with(".css") {
+                *data = convert::rewrite_references(text, &renames, name).into_bytes();
+            }
+        }
+    }
+
+    // final pass (main thread only): write every remaining entry back out
+    // (mimetype was already written first via raw_copy_file above)
+    let options = zip_options(opts);
+    let entry_data = |i: usize| -> Vec<u8> {
+        match &results[i] {
+            Some(data) => data.clone(),
+            None => match &entries[i] {
+                Entry::Done(data) => data.clone(),
+                Entry::Job(_) => unreachable!("job result missing"),
+            },
+        }
+    };
+
+    for (i, name) in final_names.into_iter().enumerate() {
+        zip.start_file(name, options.clone())?;
+        zip.write_all(&entry_data(i))?;
+    }
+
+    zip.finish()?;
+    Ok(stats)
+}
+
+/// Rejects archives that aren't actually EPUBs before any entry is touched:
+/// a real one has a `mimetype` entry whose content is exactly
+/// `application/epub+zip`, and a `META-INF/container.xml` pointing at the
+/// OPF package document.
+fn validate_epub<R: Read + Seek>(archive: &mut zip::ZipArchive<R>) -> Result<()> {
+    let not_an_epub = |reason: &str| Error::NotAnEpub(reason.to_string());
+
+    let mut mimetype = String::new();
+    archive
+        .by_name("mimetype")
+        .map_err(|_| not_an_epub("missing `mimetype` entry"))?
+        .read_to_string(&mut mimetype)
+        .map_err(|_| not_an_epub("`mimetype` entry is not valid UTF-8"))?;
+    if mimetype.trim() != "application/epub+zip" {
+        return Err(not_an_epub(&format!("unexpected mimetype `{}`", mimetype.trim())));
+    }
+
+    archive
+        .by_name("META-INF/container.xml")
+        .map_err(|_| not_an_epub("missing META-INF/container.xml"))?;
+    Ok(())
+}
+
+/// Finds the OPF package document via `META-INF/container.xml` and reads
+/// its declared `version` attribute.
+fn epub_version<R: Read + Seek>(archive: &mut zip::ZipArchive<R>) -> Option<String> {
+    let mut container = String::new();
+    archive.by_name("META-INF/container.xml").ok()?.read_to_string(&mut container).ok()?;
+    let path_start = container.find("full-path=\"")? + "full-path=\"".len();
+    let path_end = path_start + container[path_start..].find('"')?;
+    let opf_path = container[path_start..path_end].to_string();
+
+    let mut opf = String::new();
+    archive.by_name(&opf_path).ok()?.read_to_string(&mut opf).ok()?;
+    convert::opf_version(&opf).map(str::to_string)
+}